@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::Duration;
+
+/// Instrumentation for a single I/O driver.
+///
+/// Besides the fd/ready counters, this tracks per-`turn` poll-loop timing
+/// and event-batch size, which together can reveal a saturated reactor
+/// (`events`'s capacity, `nevents`, too small for the workload) that the
+/// ready counter alone wouldn't show. All of this is `pub(crate)` for now;
+/// none of it is reachable from `tokio::runtime::RuntimeMetrics` yet.
+#[derive(Default, Debug)]
+pub(crate) struct IoDriverMetrics {
+    fd_count: AtomicU64,
+    ready_count: AtomicU64,
+
+    /// Number of completed `poll.poll(...)` calls. Denominator for the mean
+    /// poll-blocked and dispatch-loop timings below.
+    poll_count: AtomicU64,
+
+    /// Total time spent blocked inside `poll.poll(...)`, in nanoseconds.
+    poll_blocked_nanos_total: AtomicU64,
+
+    /// Total time spent in the dispatch loop that follows each `poll` call,
+    /// in nanoseconds.
+    dispatch_nanos_total: AtomicU64,
+
+    /// Total number of events handed back across every `poll` call.
+    events_total: AtomicU64,
+
+    /// Largest number of events returned by a single `poll` call so far.
+    max_events_per_poll: AtomicU64,
+}
+
+impl IoDriverMetrics {
+    pub(crate) fn incr_fd_count(&self) {
+        self.fd_count.fetch_add(1, Relaxed);
+    }
+
+    pub(crate) fn dec_fd_count(&self) {
+        self.fd_count.fetch_sub(1, Relaxed);
+    }
+
+    pub(crate) fn fd_count(&self) -> u64 {
+        self.fd_count.load(Relaxed)
+    }
+
+    pub(crate) fn incr_ready_count_by(&self, amt: u64) {
+        self.ready_count.fetch_add(amt, Relaxed);
+    }
+
+    pub(crate) fn ready_count(&self) -> u64 {
+        self.ready_count.load(Relaxed)
+    }
+
+    /// Records one `turn`'s worth of time spent blocked in `poll.poll(...)`
+    /// and how many events it returned.
+    pub(crate) fn record_poll(&self, blocked: Duration, event_count: u64) {
+        self.poll_count.fetch_add(1, Relaxed);
+        self.poll_blocked_nanos_total
+            .fetch_add(blocked.as_nanos() as u64, Relaxed);
+        self.events_total.fetch_add(event_count, Relaxed);
+        self.max_events_per_poll.fetch_max(event_count, Relaxed);
+    }
+
+    /// Records how long the dispatch loop following a `poll` call took.
+    pub(crate) fn record_dispatch(&self, elapsed: Duration) {
+        self.dispatch_nanos_total
+            .fetch_add(elapsed.as_nanos() as u64, Relaxed);
+    }
+
+    pub(crate) fn poll_count(&self) -> u64 {
+        self.poll_count.load(Relaxed)
+    }
+
+    /// Mean time spent blocked in `poll.poll(...)` per `turn`, in nanoseconds.
+    pub(crate) fn mean_poll_blocked_nanos(&self) -> u64 {
+        mean(self.poll_blocked_nanos_total.load(Relaxed), self.poll_count())
+    }
+
+    /// Mean time spent in the post-poll dispatch loop per `turn`, in nanoseconds.
+    pub(crate) fn mean_dispatch_nanos(&self) -> u64 {
+        mean(self.dispatch_nanos_total.load(Relaxed), self.poll_count())
+    }
+
+    /// Mean number of events returned per `poll.poll(...)` call.
+    pub(crate) fn mean_events_per_poll(&self) -> f64 {
+        let polls = self.poll_count();
+        if polls == 0 {
+            0.0
+        } else {
+            self.events_total.load(Relaxed) as f64 / polls as f64
+        }
+    }
+
+    /// Largest number of events returned by a single `poll.poll(...)` call.
+    ///
+    /// Consistently close to `nevents` (the `events` capacity passed to
+    /// `Driver::new`) is the signal that the buffer is undersized for the
+    /// workload.
+    pub(crate) fn max_events_per_poll(&self) -> u64 {
+        self.max_events_per_poll.load(Relaxed)
+    }
+}
+
+fn mean(total: u64, count: u64) -> u64 {
+    if count == 0 {
+        0
+    } else {
+        total / count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fd_count_tracks_increments_and_decrements() {
+        let metrics = IoDriverMetrics::default();
+        metrics.incr_fd_count();
+        metrics.incr_fd_count();
+        metrics.dec_fd_count();
+        assert_eq!(metrics.fd_count(), 1);
+    }
+
+    #[test]
+    fn mean_poll_blocked_and_dispatch_nanos_average_across_polls() {
+        let metrics = IoDriverMetrics::default();
+        metrics.record_poll(Duration::from_nanos(100), 4);
+        metrics.record_dispatch(Duration::from_nanos(20));
+        metrics.record_poll(Duration::from_nanos(300), 6);
+        metrics.record_dispatch(Duration::from_nanos(60));
+
+        assert_eq!(metrics.poll_count(), 2);
+        assert_eq!(metrics.mean_poll_blocked_nanos(), 200);
+        assert_eq!(metrics.mean_dispatch_nanos(), 40);
+        assert_eq!(metrics.mean_events_per_poll(), 5.0);
+        assert_eq!(metrics.max_events_per_poll(), 6);
+    }
+
+    #[test]
+    fn means_are_zero_before_any_poll_is_recorded() {
+        let metrics = IoDriverMetrics::default();
+        assert_eq!(metrics.mean_poll_blocked_nanos(), 0);
+        assert_eq!(metrics.mean_dispatch_nanos(), 0);
+        assert_eq!(metrics.mean_events_per_poll(), 0.0);
+    }
+}
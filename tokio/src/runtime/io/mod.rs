@@ -26,18 +26,33 @@ pub(crate) struct Driver {
     /// as it is mostly used to determine when to call `compact()`.
     tick: u8,
 
+    /// Ticks since `resources.compact()` last ran; reset on every
+    /// compaction. Separate from `tick` because `tick` also tags each
+    /// `turn`'s readiness events and must keep wrapping on its own cadence.
+    ticks_since_compact: u8,
+
+    /// Thresholds deciding when `ticks_since_compact` and the dispatcher's
+    /// freed-slot count are enough to trigger `resources.compact()`.
+    compaction: IoCompactionConfig,
+
     /// True when an event with the signal token is received
     signal_ready: bool,
 
-    /// Reuse the `mio::Events` value across calls to poll.
-    events: mio::Events,
+    /// The `mio`-backed event source this driver blocks on in `turn`.
+    backend: MioBackend,
 
     /// Primary slab handle containing the state for each resource registered
     /// with this driver.
     resources: Slab<ScheduledIo>,
 
-    /// The system event queue.
-    poll: mio::Poll,
+    /// Keeps the waker's reserved slab slot alive for the lifetime of the
+    /// driver. Never read again after construction; only held so that
+    /// `resources.compact()` can't reclaim the slot out from under
+    /// `backend`'s `wakeup_addr`.
+    _wakeup_io: slab::Ref<ScheduledIo>,
+
+    /// Same as `_wakeup_io`, for the signal receiver's reserved slot.
+    _signal_io: slab::Ref<ScheduledIo>,
 }
 
 /// A reference to an I/O driver.
@@ -53,6 +68,11 @@ pub(crate) struct Handle {
     #[cfg(not(tokio_wasi))]
     waker: mio::Waker,
 
+    /// Token for the signal receiver's reserved slab slot, computed once at
+    /// construction so `register_signal_receiver` can register with it
+    /// whenever the signal driver gets around to calling it.
+    signal_token: mio::Token,
+
     pub(crate) metrics: IoDriverMetrics,
 }
 
@@ -66,6 +86,45 @@ pub(crate) struct ReadyEvent {
 struct IoDispatcher {
     allocator: slab::Allocator<ScheduledIo>,
     is_shutdown: bool,
+
+    /// Number of resources deregistered since the last time
+    /// `resources.compact()` ran. Read and reset by `Driver::turn` to decide
+    /// whether it's worth compacting yet; see `IoCompactionConfig`.
+    freed_since_compact: std::sync::atomic::AtomicUsize,
+}
+
+/// Configures when `Driver::turn` compacts the resource slab.
+///
+/// `free_threshold` lets a bursty driver compact as soon as it's worth the
+/// scan; `max_ticks` is the fallback ceiling for a driver that never frees
+/// enough resources at once to cross it on its own. See `Driver::turn` for
+/// how the two are combined.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IoCompactionConfig {
+    /// Compact once at least this many ticks have passed since the last
+    /// compaction, regardless of how much has been freed.
+    pub(crate) max_ticks: u8,
+
+    /// Compact once at least this many resources have been deregistered
+    /// since the last compaction.
+    pub(crate) free_threshold: usize,
+}
+
+impl Default for IoCompactionConfig {
+    fn default() -> Self {
+        // Mirrors the old fixed `COMPACT_INTERVAL` as the ceiling, but also
+        // lets bursty connection churn trigger compaction sooner.
+        IoCompactionConfig {
+            max_ticks: 255,
+            free_threshold: 256,
+        }
+    }
+}
+
+/// Whether `Driver::turn` should compact the resource slab this tick: either
+/// threshold configured by `config` is enough on its own.
+fn should_compact(freed: usize, ticks_since_compact: u8, config: &IoCompactionConfig) -> bool {
+    freed >= config.free_threshold || ticks_since_compact >= config.max_ticks
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -79,11 +138,6 @@ enum Tick {
     Clear(u8),
 }
 
-// TODO: Don't use a fake token. Instead, reserve a slot entry for the wakeup
-// token.
-const TOKEN_WAKEUP: mio::Token = mio::Token(1 << 31);
-const TOKEN_SIGNAL: mio::Token = mio::Token(1 + (1 << 31));
-
 const ADDRESS: bit::Pack = bit::Pack::least_significant(24);
 
 // Packs the generation value in the `readiness` field.
@@ -106,44 +160,59 @@ impl Driver {
     /// Creates a new event loop, returning any error that happened during the
     /// creation.
     pub(crate) fn new(nevents: usize) -> io::Result<(Driver, Handle)> {
-        // 创建一个 poll 用于获取事件的底层驱动
+        let compaction = IoCompactionConfig::default();
+
+        let slab = Slab::new();
+        let allocator = slab.allocator();
+
+        // Reserve the first two slab slots for the waker and the signal
+        // receiver before anything else can be registered, so that their
+        // tokens are packed through the normal `ADDRESS`/`GENERATION` scheme
+        // instead of a pair of out-of-band token values.
+        let (wakeup_addr, wakeup_io) = allocator
+            .allocate()
+            .expect("slab has no capacity immediately after construction");
+        let (signal_addr, signal_io) = allocator
+            .allocate()
+            .expect("slab has no capacity immediately after construction");
+
+        let wakeup_token = mio::Token(GENERATION.pack(
+            wakeup_io.generation(),
+            ADDRESS.pack(wakeup_addr.as_usize(), 0),
+        ));
+        let signal_token = mio::Token(GENERATION.pack(
+            signal_io.generation(),
+            ADDRESS.pack(signal_addr.as_usize(), 0),
+        ));
+
         let poll = mio::Poll::new()?;
-        // 注册 TOKEN 用于唤醒 线程 什么都不做的 事件
         #[cfg(not(tokio_wasi))]
-        let waker = mio::Waker::new(poll.registry(), TOKEN_WAKEUP)?;
-        
+        let waker = mio::Waker::new(poll.registry(), wakeup_token)?;
+
         let registry = poll.registry().try_clone()?;
-        // 创建一个 slab , 用于分配 ScheduledIO , 这种比普通分配器效率更高
-        // slab 只能用于获取对象
-        let slab = Slab::new();
-        // 创建一个 slab 分配器, 用于分配空间
-        let allocator = slab.allocator();
-        
-        // 创建一个 driver
+
         let driver = Driver {
-            // tick 用于计数, 到到默认值 255 释放 slab 空间
             tick: 0,
-            // ready 信号, 表示已经接收到了 ready 信号
+            ticks_since_compact: 0,
+            compaction,
             signal_ready: false,
-            // 事件集合, POLL 没拉取一次都会陷入 block 直到有 readiness 事件返回为止
-            // 返回的事件会 回填到 events 集合里 可以通过迭代来访问它
-            // nevents 默认为 1024
-            events: mio::Events::with_capacity(nevents),
-            poll,
-            // slab 用于通过 事件中的 token 来获取 ScheduledIo
+            backend: MioBackend {
+                events: mio::Events::with_capacity(nevents),
+                poll,
+                wakeup_addr,
+                signal_addr,
+            },
             resources: slab,
+            _wakeup_io: wakeup_io,
+            _signal_io: signal_io,
         };
 
-        // 创建一个处理器
         let handle = Handle {
-            // 用于注册事件
             registry,
-            // dispatch 用于分发事件
             io_dispatch: RwLock::new(IoDispatcher::new(allocator)),
-            // 此 waker 单纯的用于唤醒一个线程, 什么事也不干
             #[cfg(not(tokio_wasi))]
             waker,
-            // 指标
+            signal_token,
             metrics: IoDriverMetrics::default(),
         };
 
@@ -173,24 +242,85 @@ impl Driver {
     }
 
     fn turn(&mut self, handle: &Handle, max_wait: Option<Duration>) {
-        // How often to call `compact()` on the resource slab
-        // 设定 255 次归还内存给他 slab 
-        const COMPACT_INTERVAL: u8 = 255;
-
-        // compact 次数 + 1
         self.tick = self.tick.wrapping_add(1);
+        self.ticks_since_compact = self.ticks_since_compact.saturating_add(1);
+
+        let freed = handle.freed_since_compact();
+        if should_compact(freed, self.ticks_since_compact, &self.compaction) {
+            self.resources.compact();
+            self.ticks_since_compact = 0;
+            handle.reset_freed_since_compact();
+        }
+
+        self.backend.turn(
+            &mut self.resources,
+            self.tick,
+            max_wait,
+            &mut self.signal_ready,
+            &handle.metrics,
+        );
+    }
+
+    fn dispatch(resources: &mut Slab<ScheduledIo>, tick: u8, token: mio::Token, ready: Ready) {
+        let addr = slab::Address::from_usize(ADDRESS.unpack(token.0));
+
+        let io = match resources.get(addr) {
+            Some(io) => io,
+            None => return,
+        };
+
+        let res = io.set_readiness(Some(token.0), Tick::Set(tick), |curr| curr | ready);
 
-        // 判断 tick 是否达到 COMPACT_INTERVAL 也就是 255 次
-        // 如果达到 归还内存给 slab
-        if self.tick == COMPACT_INTERVAL {
-            self.resources.compact()
+        if res.is_err() {
+            // token no longer valid!
+            return;
         }
 
+        io.wake(ready);
+    }
+}
+
+impl fmt::Debug for Driver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Driver")
+    }
+}
+
+// ===== impl MioBackend =====
+
+/// The default event source, backed by `mio::Poll` (epoll on Linux, kqueue
+/// on the BSDs/macOS, IOCP on Windows).
+struct MioBackend {
+    /// Reuse the `mio::Events` value across calls to poll.
+    events: mio::Events,
+
+    /// The system event queue.
+    poll: mio::Poll,
+
+    /// Reserved slab address for the waker's token.
+    wakeup_addr: slab::Address,
+
+    /// Reserved slab address for the signal receiver's token.
+    signal_addr: slab::Address,
+}
+
+impl MioBackend {
+    /// Blocks for at most `max_wait`, then applies any readiness it
+    /// collected to `resources`, recording poll-loop timing and event-batch
+    /// size into `metrics` along the way.
+    fn turn(
+        &mut self,
+        resources: &mut Slab<ScheduledIo>,
+        tick: u8,
+        max_wait: Option<Duration>,
+        signal_ready: &mut bool,
+        metrics: &IoDriverMetrics,
+    ) {
         let events = &mut self.events;
 
         // Block waiting for an event to happen, peeling out how many events
         // happened.
-        // 调用底层 等待 readiness 的事件
+        let poll_started_at = std::time::Instant::now();
         match self.poll.poll(events, max_wait) {
             Ok(_) => {}
             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
@@ -201,60 +331,32 @@ impl Driver {
             }
             Err(e) => panic!("unexpected error when polling the I/O driver: {:?}", e),
         }
+        let poll_blocked = poll_started_at.elapsed();
 
         // Process all the events that came in, dispatching appropriately
+        let dispatch_started_at = std::time::Instant::now();
         let mut ready_count = 0;
-        // 遍历所有 readiness 事件
+        let mut event_count = 0;
         for event in events.iter() {
-            // 获取事件中的 token
+            event_count += 1;
+
             let token = event.token();
-            // 如果 token 等于 TOKEN_WAKEUP 什么事都不用做 单纯用于唤醒此线程
-            if token == TOKEN_WAKEUP {
+            let addr = slab::Address::from_usize(ADDRESS.unpack(token.0));
+
+            if addr == self.wakeup_addr {
                 // Nothing to do, the event is used to unblock the I/O driver
-            } else if token == TOKEN_SIGNAL {
-                // 如果 token 等于 TOKEN_SIGNAL 那么 signal_ready 设置为 true
-                self.signal_ready = true;
+            } else if addr == self.signal_addr {
+                *signal_ready = true;
             } else {
-                // dispatch 时间
-                Self::dispatch(
-                    &mut self.resources,
-                    self.tick,
-                    token,
-                    Ready::from_mio(event),
-                );
-                // ready count +1 用于设置指标
+                Driver::dispatch(resources, tick, token, Ready::from_mio(event));
                 ready_count += 1;
             }
         }
+        let dispatch_elapsed = dispatch_started_at.elapsed();
 
-        handle.metrics.incr_ready_count_by(ready_count);
-    }
-
-    fn dispatch(resources: &mut Slab<ScheduledIo>, tick: u8, token: mio::Token, ready: Ready) {
-        // 取 token 中的 右24位 为 slab 索引地址
-        let addr = slab::Address::from_usize(ADDRESS.unpack(token.0));
-        
-        // 通过索引获取 slab 中的 ScheduledIO
-        let io = match resources.get(addr) {
-            Some(io) => io,
-            None => return,
-        };
-
-        // 设置 ScheduledIO 为 readiness
-        let res = io.set_readiness(Some(token.0), Tick::Set(tick), |curr| curr | ready);
-
-        if res.is_err() {
-            // token no longer valid!
-            return;
-        }
-
-        io.wake(ready);
-    }
-}
-
-impl fmt::Debug for Driver {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Driver")
+        metrics.record_poll(poll_blocked, event_count);
+        metrics.record_dispatch(dispatch_elapsed);
+        metrics.incr_ready_count_by(ready_count);
     }
 }
 
@@ -298,10 +400,38 @@ impl Handle {
         self.registry.deregister(source)?;
 
         self.metrics.dec_fd_count();
+        self.io_dispatch
+            .read()
+            .unwrap()
+            .freed_since_compact
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
         Ok(())
     }
 
+    /// Returns the number of resources deregistered since the last
+    /// compaction, without resetting the count.
+    ///
+    /// Used by `Driver::turn` to decide whether `IoCompactionConfig`'s
+    /// `free_threshold` has been crossed.
+    fn freed_since_compact(&self) -> usize {
+        self.io_dispatch
+            .read()
+            .unwrap()
+            .freed_since_compact
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Resets the freed-resource count back to zero; called once `Driver::turn`
+    /// has actually compacted the slab.
+    fn reset_freed_since_compact(&self) {
+        self.io_dispatch
+            .read()
+            .unwrap()
+            .freed_since_compact
+            .store(0, std::sync::atomic::Ordering::Relaxed);
+    }
+
     /// shutdown the dispatcher.
     fn shutdown(&self) -> bool {
         let mut io = self.io_dispatch.write().unwrap();
@@ -342,6 +472,7 @@ impl IoDispatcher {
         Self {
             allocator,
             is_shutdown: false,
+            freed_since_compact: std::sync::atomic::AtomicUsize::new(0),
         }
     }
 }
@@ -359,7 +490,8 @@ impl Direction {
 cfg_signal_internal_and_unix! {
     impl Handle {
         pub(crate) fn register_signal_receiver(&self, receiver: &mut mio::net::UnixStream) -> io::Result<()> {
-            self.registry.register(receiver, TOKEN_SIGNAL, mio::Interest::READABLE)?;
+            self.registry
+                .register(receiver, self.signal_token, mio::Interest::READABLE)?;
             Ok(())
         }
     }
@@ -372,3 +504,99 @@ cfg_signal_internal_and_unix! {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packed_token(generation: usize, address: usize) -> mio::Token {
+        mio::Token(GENERATION.pack(generation, ADDRESS.pack(address, 0)))
+    }
+
+    #[test]
+    fn reserved_wakeup_and_signal_addresses_unpack_distinctly() {
+        // `Driver::new` always allocates the waker's slot before the signal
+        // receiver's, so they land on addresses 0 and 1 respectively.
+        let wakeup_token = packed_token(0, 0);
+        let signal_token = packed_token(0, 1);
+
+        let wakeup_addr = slab::Address::from_usize(ADDRESS.unpack(wakeup_token.0));
+        let signal_addr = slab::Address::from_usize(ADDRESS.unpack(signal_token.0));
+
+        assert_ne!(wakeup_addr, signal_addr);
+        assert_eq!(wakeup_addr, slab::Address::from_usize(0));
+        assert_eq!(signal_addr, slab::Address::from_usize(1));
+    }
+
+    #[test]
+    fn address_unpack_ignores_the_generation_bits() {
+        // A resource's token carries its generation in the high bits; two
+        // tokens for the same address but different generations must still
+        // unpack to the same `Address`, or MioBackend::turn would never
+        // recognize a re-registered wakeup/signal slot.
+        let first = packed_token(0, 5);
+        let second = packed_token(3, 5);
+
+        assert_eq!(
+            ADDRESS.unpack(first.0),
+            ADDRESS.unpack(second.0)
+        );
+    }
+
+    #[test]
+    fn add_source_never_hands_out_a_reserved_address() {
+        let (_driver, handle) = Driver::new(1024).expect("failed to create driver");
+
+        // `add_source` allocates through `Handle::allocate`; addresses 0 and
+        // 1 are reserved for the waker and signal receiver by `Driver::new`
+        // and must never be handed out to a real resource.
+        let (address, _io) = handle
+            .allocate()
+            .expect("failed to allocate a resource slot");
+
+        assert_ne!(address, slab::Address::from_usize(0));
+        assert_ne!(address, slab::Address::from_usize(1));
+    }
+
+    #[test]
+    fn reserved_addresses_stay_unavailable_after_a_compaction() {
+        let (mut driver, handle) = Driver::new(1024).expect("failed to create driver");
+
+        let (first_address, first_io) = handle
+            .allocate()
+            .expect("failed to allocate a resource slot");
+        drop(first_io);
+        driver.resources.compact();
+
+        let (second_address, _second_io) = handle
+            .allocate()
+            .expect("failed to allocate a resource slot");
+
+        assert_ne!(first_address, slab::Address::from_usize(0));
+        assert_ne!(first_address, slab::Address::from_usize(1));
+        assert_ne!(second_address, slab::Address::from_usize(0));
+        assert_ne!(second_address, slab::Address::from_usize(1));
+    }
+
+    fn config(max_ticks: u8, free_threshold: usize) -> IoCompactionConfig {
+        IoCompactionConfig {
+            max_ticks,
+            free_threshold,
+        }
+    }
+
+    #[test]
+    fn should_compact_is_false_below_both_thresholds() {
+        assert!(!should_compact(10, 5, &config(255, 256)));
+    }
+
+    #[test]
+    fn should_compact_is_true_once_free_threshold_is_crossed() {
+        assert!(should_compact(256, 1, &config(255, 256)));
+    }
+
+    #[test]
+    fn should_compact_is_true_once_max_ticks_is_crossed_even_with_nothing_freed() {
+        assert!(should_compact(0, 255, &config(255, 256)));
+    }
+}